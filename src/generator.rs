@@ -0,0 +1,267 @@
+// Version 3 target-melody generator.
+//
+// Given a recorded accompaniment and one of the user's solos over it, build a
+// new line that keeps the solo's rhythm but invents pitches: it starts on the
+// solo's first pitch, then for each note either jumps to a random tone of the
+// chord's scale or continues the current melodic figure by repeating the last
+// interval (snapped back into the scale). The final notes are steered so the
+// line resolves onto the same pitch the solo ended on. `generate` returns a
+// `Recording` that can be played back like any other, and `score_distance`
+// grades the user's attempt by how close each pitch lands.
+
+use crate::scripting::{NoteContext, ScriptEngine};
+use midi_msg::MidiMsg;
+use midi_note_recorder::Recording;
+use music_analyzer_generator::{ChordName, PitchSequence};
+
+// Chance of leaping to a random scale tone rather than continuing the figure.
+const RANDOM_NOTE_PROBABILITY: f64 = 0.5;
+// Length of the closing span: its first note is kept as the anchor and the
+// notes after it are bent toward the resolution pitch.
+const RESOLUTION_NOTES: usize = 4;
+
+const MAJOR_SCALE: [u8; 7] = [0, 2, 4, 5, 7, 9, 11];
+const MINOR_SCALE: [u8; 7] = [0, 2, 3, 5, 7, 8, 10];
+
+struct Note {
+    on: f64,
+    off: f64,
+    pitch: u8,
+    velocity: u8,
+}
+
+// Build the target line. When `script` supplies a `next_note` rule we take the
+// pitch it returns (clamped into MIDI range); otherwise we fall back to the
+// built-in figure-and-leap rule.
+pub fn generate(
+    accompaniment: &Recording,
+    solo: &Recording,
+    script: &mut Option<ScriptEngine>,
+) -> Recording {
+    let source = notes(solo);
+    let spans = chord_spans(accompaniment);
+    let mut result = Recording::default();
+    if source.is_empty() {
+        return result;
+    }
+
+    let mut pitches = vec![source[0].pitch];
+    let mut last_interval: i32 = 0;
+    for note in source.iter().skip(1) {
+        let chord = chord_at(&spans, note.on);
+        let scale = scale_pitches(chord.clone());
+        let prev = *pitches.last().unwrap();
+        let scripted = script.as_mut().and_then(|engine| {
+            let ctx = NoteContext {
+                chord: chord.as_ref().map_or_else(String::new, |c| format!("{c}")),
+                in_scale: scale.contains(&prev),
+                figure: figure_window(&pitches),
+                elapsed: note.on,
+            };
+            engine.next_note(&ctx)
+        });
+        let pitch = match scripted {
+            Some(p) => p.clamp(0, 127) as u8,
+            None if last_interval == 0 || rand() < RANDOM_NOTE_PROBABILITY => {
+                random_scale_pitch(&scale, prev)
+            }
+            None => snap_to_scale(prev as i32 + last_interval, &scale),
+        };
+        last_interval = pitch as i32 - prev as i32;
+        pitches.push(pitch);
+    }
+
+    resolve(&mut pitches, source.last().unwrap().pitch, &spans, &source);
+
+    let mut events = vec![];
+    for (note, pitch) in source.iter().zip(pitches) {
+        events.push((note.on, message(0x90, pitch, note.velocity)));
+        events.push((note.off, message(0x80, pitch, 0)));
+    }
+    events.sort_by(|a, b| a.0.total_cmp(&b.0));
+    for (time, msg) in events {
+        result.add_message(time, &msg);
+    }
+    result
+}
+
+// Total pitch distance between a generated target and the user's attempt,
+// paired note-for-note in order (lower is a closer match).
+pub fn score_distance(target: &Recording, attempt: &Recording) -> i64 {
+    notes(target)
+        .iter()
+        .zip(notes(attempt))
+        .map(|(t, a)| (t.pitch as i64 - a.pitch as i64).abs())
+        .sum()
+}
+
+// Bend the last few pitches so the line lands on `target`, stepping evenly
+// from the last free note and snapping each onto the active chord's scale.
+fn resolve(pitches: &mut [u8], target: u8, spans: &[(ChordName, f64)], source: &[Note]) {
+    let len = pitches.len();
+    // A single-note line already starts and ends on the source's first pitch.
+    if len <= 1 {
+        return;
+    }
+    let span = RESOLUTION_NOTES.min(len);
+    let anchor = pitches[len - span] as i32;
+    for step in 1..span {
+        let i = len - span + step;
+        let blended = anchor + (target as i32 - anchor) * step as i32 / span as i32;
+        pitches[i] = snap_to_scale(blended, &scale_pitches(chord_at(spans, source[i].on)));
+    }
+    pitches[len - 1] = target;
+}
+
+// The recent melodic intervals (up to the last four), most recent last, used
+// to describe the shape of the line to a scoring or generation script.
+fn figure_window(pitches: &[u8]) -> Vec<i64> {
+    let mut figure: Vec<i64> = pitches
+        .windows(2)
+        .rev()
+        .take(4)
+        .map(|w| w[1] as i64 - w[0] as i64)
+        .collect();
+    figure.reverse();
+    figure
+}
+
+// Describe each note of a solo in the harmonic context of its accompaniment,
+// so a `score_note` script (or the built-in rule) can grade the performance.
+pub fn note_contexts(accompaniment: &Recording, solo: &Recording) -> Vec<NoteContext> {
+    let source = notes(solo);
+    let spans = chord_spans(accompaniment);
+    let mut pitches = vec![];
+    let mut contexts = vec![];
+    for note in &source {
+        let chord = chord_at(&spans, note.on);
+        let scale = scale_pitches(chord.clone());
+        // Include this note so the figure ends with the interval that led to it.
+        pitches.push(note.pitch);
+        contexts.push(NoteContext {
+            chord: chord.as_ref().map_or_else(String::new, |c| format!("{c}")),
+            in_scale: scale.contains(&note.pitch),
+            figure: figure_window(&pitches),
+            elapsed: note.on,
+        });
+    }
+    contexts
+}
+
+fn notes(recording: &Recording) -> Vec<Note> {
+    let mut result = vec![];
+    let mut active: std::collections::HashMap<u8, (f64, u8)> = std::collections::HashMap::new();
+    for (time, msg) in recording.messages() {
+        let bytes = msg.to_midi();
+        if bytes.len() < 3 {
+            continue;
+        }
+        let (status, pitch, velocity) = (bytes[0] & 0xf0, bytes[1], bytes[2]);
+        match status {
+            0x90 if velocity > 0 => {
+                active.insert(pitch, (*time, velocity));
+            }
+            0x80 | 0x90 => {
+                if let Some((on, velocity)) = active.remove(&pitch) {
+                    result.push(Note { on, off: *time, pitch, velocity });
+                }
+            }
+            _ => {}
+        }
+    }
+    result.sort_by(|a, b| a.on.total_cmp(&b.on));
+    result
+}
+
+// Collapse the accompaniment into (chord, start) spans, one per chord change.
+fn chord_spans(accompaniment: &Recording) -> Vec<(ChordName, f64)> {
+    let mut result: Vec<(ChordName, f64)> = vec![];
+    for (chord, start, _) in PitchSequence::new(accompaniment).chords_starts_durations() {
+        if result.last().map_or(true, |(name, _)| *name != chord.name()) {
+            result.push((chord.name(), start));
+        }
+    }
+    result
+}
+
+fn chord_at(spans: &[(ChordName, f64)], time: f64) -> Option<ChordName> {
+    spans
+        .iter()
+        .rev()
+        .find(|(_, start)| *start <= time)
+        .or_else(|| spans.first())
+        .map(|(name, _)| name.clone())
+}
+
+// Every MIDI pitch whose pitch class belongs to the chord's diatonic scale.
+// With no known chord we fall back to the full chromatic set.
+fn scale_pitches(chord: Option<ChordName>) -> Vec<u8> {
+    let Some(chord) = chord else {
+        return (0..=127).collect();
+    };
+    let name = format!("{chord}");
+    let root = root_pc(&name);
+    let scale = if is_minor(&name) { MINOR_SCALE } else { MAJOR_SCALE };
+    (0..=127)
+        .filter(|p| scale.contains(&((p + 12 - root) % 12)))
+        .collect()
+}
+
+fn root_pc(name: &str) -> u8 {
+    let bytes = name.as_bytes();
+    let letter = match bytes.first() {
+        Some(b'C') => 0,
+        Some(b'D') => 2,
+        Some(b'E') => 4,
+        Some(b'F') => 5,
+        Some(b'G') => 7,
+        Some(b'A') => 9,
+        Some(b'B') => 11,
+        _ => 0,
+    };
+    let accidental = match bytes.get(1) {
+        Some(b'#') => 1,
+        Some(b'b') => -1,
+        _ => 0,
+    };
+    ((letter + accidental).rem_euclid(12)) as u8
+}
+
+fn is_minor(name: &str) -> bool {
+    // A lowercase 'm' marks a minor chord, but not the 'm' in "maj".
+    match name.find('m') {
+        Some(i) => !name[i..].starts_with("maj"),
+        None => false,
+    }
+}
+
+fn random_scale_pitch(scale: &[u8], near: u8) -> u8 {
+    let window: Vec<u8> = scale
+        .iter()
+        .copied()
+        .filter(|p| p.abs_diff(near) <= 12)
+        .collect();
+    let choices = if window.is_empty() { scale } else { &window };
+    if choices.is_empty() {
+        near
+    } else {
+        choices[(rand() * choices.len() as f64) as usize % choices.len()]
+    }
+}
+
+fn snap_to_scale(pitch: i32, scale: &[u8]) -> u8 {
+    let pitch = pitch.clamp(0, 127) as u8;
+    scale
+        .iter()
+        .copied()
+        .min_by_key(|p| p.abs_diff(pitch))
+        .unwrap_or(pitch)
+}
+
+fn message(status: u8, pitch: u8, velocity: u8) -> MidiMsg {
+    MidiMsg::from_midi(&[status, pitch, velocity]).unwrap().0
+}
+
+fn rand() -> f64 {
+    rand::random::<f64>()
+}
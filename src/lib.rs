@@ -1,4 +1,8 @@
+pub mod generator;
 pub mod recorder;
+pub mod scripting;
+pub mod smf;
+pub mod wav;
 
 use std::path::PathBuf;
 
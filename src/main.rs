@@ -1,26 +1,34 @@
 use std::{
+    path::PathBuf,
     sync::{Arc, Mutex},
-    time::Instant,
+    time::Duration,
 };
 
 use crossbeam_queue::SegQueue;
 use crossbeam_utils::atomic::AtomicCell;
 use eframe::egui::{self, Pos2, Vec2, Visuals};
+use enum_iterator::all;
 use midi_fundsp::{
-    io::{SynthMsg, get_first_midi_device, start_input_thread, start_output_thread},
+    io::{get_first_midi_device, start_input_thread, start_output_thread, Speaker, SynthMsg},
     sounds::options,
 };
-use midi_improv_hero::setup_font;
+use midi_improv_hero::{
+    recorder::{ControlTarget, Recorder, RecordingMode, MAX_TIMEOUT, MIN_TIMEOUT},
+    setup_font,
+};
 use midi_note_recorder::Recording;
-use midir::MidiInput;
+use midir::{MidiInput, MidiInputPort};
 use music_analyzer_generator::{ChordName, PitchSequence};
 
-const MIN_TIMEOUT: f64 = 1.0;
-const MAX_TIMEOUT: f64 = 5.0;
 const DEFAULT_TIMEOUT: f64 = MIN_TIMEOUT;
 const NUM_CHANNELS: usize = 10;
+const LAST_DEVICE_FILE: &str = "last_device.txt";
+const MIN_BPM: f64 = 40.0;
+const MAX_BPM: f64 = 240.0;
 const FPS: f32 = 20.0;
 const FRAME_INTERVAL: f32 = 1.0 / FPS;
+// How long the merge relay naps when its queue is empty, so it doesn't spin.
+const MERGE_POLL: Duration = Duration::from_millis(1);
 
 // Vision for this program
 //
@@ -68,91 +76,143 @@ fn main() {
     .unwrap();
 }
 
-struct Recorder {
-    recordings: Vec<Recording>,
-    timeout: f64,
-    last_msg: Instant,
-    current_start: Instant,
-    input_port_name: String,
-    mode: RecordingMode,
-}
-
-impl Recorder {
-    fn new(timeout: f64, input_port_name: String) -> Self {
-        Self {
-            timeout,
-            recordings: vec![],
-            last_msg: Instant::now(),
-            current_start: Instant::now(),
-            input_port_name,
-            mode: RecordingMode::Playthrough,
-        }
-    }
-
-    fn in_recording_mode(&self) -> bool {
-        self.mode == RecordingMode::Record
-    }
-
-    fn actively_recording(&self) -> bool {
-        self.in_recording_mode()
-            && !self.recordings.is_empty()
-            && Instant::now().duration_since(self.last_msg).as_secs_f64() < self.timeout
-    }
-
-    fn receive(&mut self, msg: SynthMsg) {
-        if self.in_recording_mode() {
-            let now = Instant::now();
-            if !self.actively_recording() {
-                self.recordings.push(Recording::default());
-                self.current_start = now;
-            }
-            self.recordings.last_mut().unwrap().add_message(
-                now.duration_since(self.current_start).as_secs_f64(),
-                &msg.msg,
-            );
-            self.last_msg = now;
-        }
-    }
-}
-
 struct GameApp {
     recorder: Arc<Mutex<Recorder>>,
-    selected_recording: usize,
+    input2monitor: Arc<SegQueue<SynthMsg>>,
+    input_quit: Arc<AtomicCell<bool>>,
+    extra_inputs: Vec<Arc<AtomicCell<bool>>>,
+    ports: Vec<String>,
+    status: Option<String>,
+    target: Option<Recording>,
 }
 
 impl GameApp {
     fn new(cc: &eframe::CreationContext<'_>) -> anyhow::Result<Self> {
         setup_font("bravura/BravuraText.otf", cc)?;
-        Ok(Self {
-            recorder: Self::setup_threads()?,
-            selected_recording: 0,
-        })
+        Self::setup_threads()
     }
 
     fn port_name(&self) -> String {
-        self.recorder.lock().unwrap().input_port_name.clone()
+        self.recorder.lock().unwrap().input_port_name().to_string()
     }
 
-    fn setup_threads() -> anyhow::Result<Arc<Mutex<Recorder>>> {
-        let mut midi_in = MidiInput::new("midir reading input")?;
-        let in_port = get_first_midi_device(&mut midi_in)?;
+    fn setup_threads() -> anyhow::Result<Self> {
         let input2monitor = Arc::new(SegQueue::new());
         let monitor2output = Arc::new(SegQueue::new());
-        let quit = Arc::new(AtomicCell::new(false));
+        let monitor_quit = Arc::new(AtomicCell::new(false));
+
+        // Reconnect to the device we used last time if it is still present.
+        let mut midi_in = MidiInput::new("midir reading input")?;
+        let in_port = choose_port(&mut midi_in, load_last_device().as_deref())?;
+        let port_name = midi_in.port_name(&in_port)?;
+
         let recorder = Arc::new(Mutex::new(Recorder::new(
             DEFAULT_TIMEOUT,
-            midi_in.port_name(&in_port)?,
+            input2monitor.clone(),
+            monitor2output.clone(),
+            port_name,
         )));
-        start_input_thread(input2monitor.clone(), midi_in, in_port, quit.clone());
+        let input_quit = Arc::new(AtomicCell::new(false));
+        start_input_thread(input2monitor.clone(), midi_in, in_port, input_quit.clone());
         start_monitor_thread(
-            input2monitor,
+            input2monitor.clone(),
             monitor2output.clone(),
-            quit,
+            monitor_quit,
             recorder.clone(),
         );
         start_output_thread::<NUM_CHANNELS>(monitor2output, Arc::new(Mutex::new(options())));
-        Ok(recorder)
+        Ok(Self {
+            recorder,
+            input2monitor,
+            input_quit,
+            extra_inputs: vec![],
+            ports: list_ports().unwrap_or_default(),
+            status: None,
+            target: None,
+        })
+    }
+
+    // Switch the active input. The new port and thread are secured first, so a
+    // failed open leaves the current device running untouched; the old thread
+    // is torn down only once the replacement is live.
+    fn switch_port(&mut self, name: &str) -> anyhow::Result<()> {
+        let mut midi_in = MidiInput::new("midir reading input")?;
+        let in_port = choose_port(&mut midi_in, Some(name))?;
+        let port_name = midi_in.port_name(&in_port)?;
+        let input_quit = Arc::new(AtomicCell::new(false));
+        start_input_thread(self.input2monitor.clone(), midi_in, in_port, input_quit.clone());
+        self.input_quit.store(true);
+        self.input_quit = input_quit;
+        self.recorder.lock().unwrap().set_input_port_name(&port_name);
+        save_last_device(&port_name);
+        Ok(())
+    }
+
+    // Run an additional input thread for a second controller, tagging its
+    // messages with `Speaker::Right` so downstream can tell the two keyboards
+    // apart (the primary device stays `Speaker::Both`).
+    fn merge_port(&mut self, name: &str) -> anyhow::Result<()> {
+        let mut midi_in = MidiInput::new("midir reading input")?;
+        let in_port = choose_port(&mut midi_in, Some(name))?;
+        let quit = Arc::new(AtomicCell::new(false));
+        let tagged = Arc::new(SegQueue::new());
+        start_input_thread(tagged.clone(), midi_in, in_port, quit.clone());
+        let target = self.input2monitor.clone();
+        let relay_quit = quit.clone();
+        std::thread::spawn(move || {
+            while !relay_quit.load() {
+                match tagged.pop() {
+                    Some(mut msg) => {
+                        msg.speaker = Speaker::Right;
+                        target.push(msg);
+                    }
+                    None => std::thread::sleep(MERGE_POLL),
+                }
+            }
+        });
+        self.extra_inputs.push(quit);
+        Ok(())
     }
+
+    // Signal every merged device's input and relay threads to stop, then drop
+    // the flags so a later merge starts clean.
+    fn clear_merged(&mut self) {
+        for quit in self.extra_inputs.drain(..) {
+            quit.store(true);
+        }
+    }
+}
+
+fn list_ports() -> anyhow::Result<Vec<String>> {
+    let midi_in = MidiInput::new("midir port list")?;
+    midi_in
+        .ports()
+        .iter()
+        .map(|port| midi_in.port_name(port).map_err(Into::into))
+        .collect()
+}
+
+// Prefer a port matching `preferred`, falling back to the first device.
+fn choose_port(midi_in: &mut MidiInput, preferred: Option<&str>) -> anyhow::Result<MidiInputPort> {
+    if let Some(name) = preferred {
+        for port in midi_in.ports() {
+            if midi_in.port_name(&port).map_or(false, |n| n == name) {
+                return Ok(port);
+            }
+        }
+    }
+    Ok(get_first_midi_device(midi_in)?)
+}
+
+fn load_last_device() -> Option<String> {
+    std::fs::read_to_string(LAST_DEVICE_FILE)
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+fn save_last_device(name: &str) {
+    let _ = std::fs::write(LAST_DEVICE_FILE, name);
 }
 
 fn start_monitor_thread(
@@ -164,9 +224,18 @@ fn start_monitor_thread(
     std::thread::spawn(move || {
         while !quit.load() {
             if let Some(msg) = incoming.pop() {
-                outgoing.push(msg.clone());
+                let bytes = msg.msg.to_midi();
                 let mut recorder = recorder.lock().unwrap();
-                recorder.receive(msg);
+                if bytes.len() >= 3
+                    && bytes[0] & 0xf0 == 0xb0
+                    && recorder.handle_cc(bytes[1], bytes[2])
+                {
+                    // Control-change bound to a parameter: consumed, not played.
+                } else {
+                    // Everything else — including unbound CCs — plays as normal.
+                    outgoing.push(msg.clone());
+                    recorder.receive(msg);
+                }
             }
         }
     });
@@ -176,29 +245,76 @@ fn label(ui: &mut egui::Ui, text: &str) {
     ui.add(egui::Label::new(text));
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
-enum RecordingMode {
-    Playthrough,
-    Record,
-}
-
 impl eframe::App for GameApp {
     fn update(&mut self, ctx: &eframe::egui::Context, _frame: &mut eframe::Frame) {
         ctx.set_visuals(Visuals::light());
         egui::CentralPanel::default().show(ctx, |ui| {
             let heading = format!("MIDI Improv Hero ({})", self.port_name());
             ui.heading(heading);
+
+            let current = self.port_name();
+            let mut chosen = current.clone();
+            let mut merge: Option<String> = None;
+            ui.horizontal(|ui| {
+                ui.label("Input device");
+                egui::ComboBox::from_id_salt("input_device")
+                    .selected_text(current.as_str())
+                    .show_ui(ui, |ui| {
+                        for port in &self.ports {
+                            ui.selectable_value(&mut chosen, port.clone(), port.as_str());
+                        }
+                    });
+                if ui.button("Refresh").clicked() {
+                    self.ports = list_ports().unwrap_or_default();
+                }
+                ui.menu_button("Merge device", |ui| {
+                    for port in &self.ports {
+                        if ui.button(port.as_str()).clicked() {
+                            merge = Some(port.clone());
+                            ui.close_menu();
+                        }
+                    }
+                });
+                if !self.extra_inputs.is_empty()
+                    && ui.button("Stop merged devices").clicked()
+                {
+                    self.clear_merged();
+                }
+            });
+            if chosen != current {
+                if let Err(e) = self.switch_port(&chosen) {
+                    self.status = Some(format!("Could not switch to {chosen}: {e}"));
+                } else {
+                    self.status = None;
+                }
+            }
+            if let Some(name) = merge {
+                if let Err(e) = self.merge_port(&name) {
+                    self.status = Some(format!("Could not merge {name}: {e}"));
+                }
+            }
+            if let Some(status) = &self.status {
+                label(ui, status.as_str());
+            }
+
             let mut recorder = self.recorder.lock().unwrap();
-            ui.radio_value(
-                &mut recorder.mode,
-                RecordingMode::Playthrough,
-                "Play Freely",
-            );
-            ui.radio_value(
-                &mut recorder.mode,
-                RecordingMode::Record,
-                "Record Accompaniment",
-            );
+            for mode in all::<RecordingMode>() {
+                ui.radio_value(&mut recorder.mode, mode, mode.text());
+            }
+            ui.collapsing("MIDI Control Mapping", |ui| {
+                for (i, binding) in recorder.cc_bindings.iter_mut().enumerate() {
+                    ui.horizontal(|ui| {
+                        ui.add(egui::Slider::new(&mut binding.controller, 0..=127).text("CC"));
+                        egui::ComboBox::from_id_salt(i)
+                            .selected_text(binding.target.text())
+                            .show_ui(ui, |ui| {
+                                for target in all::<ControlTarget>() {
+                                    ui.selectable_value(&mut binding.target, target, target.text());
+                                }
+                            });
+                    });
+                }
+            });
             match recorder.mode {
                 RecordingMode::Record => {
                     let timeout = recorder.timeout;
@@ -208,29 +324,93 @@ impl eframe::App for GameApp {
                             .text(format!("Recording stops after {timeout} {suffix}"))
                             .show_value(false),
                     );
+                    let mut metronome_on = recorder.metronome_on;
+                    if ui.checkbox(&mut metronome_on, "Metronome").changed() {
+                        recorder.set_metronome(metronome_on);
+                    }
+                    let mut bpm = recorder.bpm;
+                    if ui
+                        .add(egui::Slider::new(&mut bpm, MIN_BPM..=MAX_BPM).text("BPM"))
+                        .changed()
+                    {
+                        recorder.set_bpm(bpm);
+                    }
+                    ui.add(
+                        egui::Slider::new(&mut recorder.count_in, 0..=8)
+                            .integer()
+                            .text("Count-in beats"),
+                    );
                     if recorder.actively_recording() {
                         label(ui, "recording in progress");
-                    } else if recorder.recordings.is_empty() {
+                    } else if recorder.is_empty() {
                         label(ui, "No recordings");
                     } else {
-                        let current = if recorder.recordings.len() == 1 {
+                        let selected = if recorder.len() == 1 {
                             label(ui, "One recording");
-                            &recorder.recordings[0]
+                            0
                         } else {
-                            let recs = format!("{} recordings", recorder.recordings.len());
+                            let recs = format!("{} recordings", recorder.len());
                             label(ui, recs.as_str());
                             ui.heading("Select a Recording");
+                            let max = recorder.len() - 1;
                             ui.add(
-                                egui::Slider::new(
-                                    &mut self.selected_recording,
-                                    0..=recorder.recordings.len() - 1,
-                                )
-                                .integer(),
+                                egui::Slider::new(&mut recorder.selected_recording, 0..=max)
+                                    .integer(),
                             );
-                            &recorder.recordings[self.selected_recording]
+                            recorder.selected_recording
                         };
-                        let cs = format!("{}", chords_starts_string(current));
+                        let cs = format!("{}", chords_starts_string(&recorder[selected]));
                         label(ui, cs.as_str());
+                        if ui.button("Export Audio (approx. tone)").clicked() {
+                            let path = PathBuf::from(format!("accompaniment{selected}.wav"));
+                            let solo = recorder.last_solo_over(selected);
+                            if let Err(e) = recorder.save_wav(selected, solo, &path) {
+                                label(ui, format!("Export failed: {e}").as_str());
+                            }
+                        }
+                        if ui.button("Export MIDI").clicked() {
+                            let path = PathBuf::from(format!("accompaniment{selected}.mid"));
+                            if let Err(e) = recorder.save_smf(selected, &path) {
+                                label(ui, format!("Export failed: {e}").as_str());
+                            }
+                        }
+                    }
+                    ctx.request_repaint_after_secs(FRAME_INTERVAL);
+                }
+                RecordingMode::SoloOver => {
+                    if recorder.is_empty() {
+                        label(ui, "Record an accompaniment first");
+                    } else {
+                        let max = recorder.len() - 1;
+                        ui.add(
+                            egui::Slider::new(&mut recorder.selected_recording, 0..=max).integer(),
+                        );
+                        let selected = recorder.selected_recording;
+                        if ui.button("Solo Over Recording").clicked() {
+                            recorder.start_solo_thread(selected);
+                        }
+                        // Only ever act on a solo recorded over this selection.
+                        if let Some(solo) = recorder.last_solo_over(selected) {
+                            let score = recorder.score_solo(selected, solo);
+                            label(ui, format!("Solo score: {score}").as_str());
+                            if ui.button("Generate Target").clicked() {
+                                let target = recorder.generate_target(selected, solo);
+                                recorder.start_target_thread(selected, target.clone());
+                                self.target = Some(target);
+                            }
+                            if ui.button("Export MIDI + Solo").clicked() {
+                                let path =
+                                    PathBuf::from(format!("accompaniment{selected}_solo.mid"));
+                                if let Err(e) = recorder.save_smf_with_solo(selected, solo, &path) {
+                                    label(ui, format!("Export failed: {e}").as_str());
+                                }
+                            }
+                        }
+                        if let Some(target) = &self.target {
+                            if let Some(score) = recorder.score_last_attempt(target) {
+                                label(ui, format!("Pitch distance: {score}").as_str());
+                            }
+                        }
                     }
                     ctx.request_repaint_after_secs(FRAME_INTERVAL);
                 }
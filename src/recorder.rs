@@ -1,8 +1,35 @@
+use crate::{
+    generator,
+    scripting::{NoteContext, ScriptEngine},
+    smf, wav,
+};
 use crossbeam_queue::SegQueue;
-use enum_iterator::Sequence;
+use crossbeam_utils::atomic::AtomicCell;
+use enum_iterator::{first, next, Sequence};
 use midi_fundsp::io::SynthMsg;
+use midi_msg::MidiMsg;
 use midi_note_recorder::Recording;
-use std::{ops::Index, sync::Arc, time::Instant};
+use std::{
+    collections::HashMap,
+    f32::consts::TAU,
+    io,
+    ops::Index,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+pub const MIN_TIMEOUT: f64 = 1.0;
+pub const MAX_TIMEOUT: f64 = 5.0;
+
+const SAMPLE_RATE: u32 = 44100;
+
+// The metronome reserves the last of the output channels so its clicks never
+// collide with a played note.
+const METRONOME_CHANNEL: u8 = 9;
+const CLICK_NOTE: u8 = 76;
+const CLICK_VELOCITY: u8 = 100;
+const CLICK_LENGTH: Duration = Duration::from_millis(40);
 
 #[derive(Sequence, Copy, Clone, PartialEq, Eq, Debug)]
 pub enum RecordingMode {
@@ -21,17 +48,54 @@ impl RecordingMode {
     }
 }
 
+// App parameters a MIDI control-change message can drive hands-free.
+#[derive(Sequence, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ControlTarget {
+    Timeout,
+    CycleMode,
+    SelectRecording,
+}
+
+impl ControlTarget {
+    pub fn text(&self) -> &'static str {
+        match self {
+            Self::Timeout => "Recording timeout",
+            Self::CycleMode => "Cycle mode",
+            Self::SelectRecording => "Select recording",
+        }
+    }
+}
+
+// Maps a controller number to the parameter its 0..=127 value should drive.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct CcBinding {
+    pub controller: u8,
+    pub target: ControlTarget,
+}
+
 pub struct Recorder {
     pub timeout: f64,
     pub mode: RecordingMode,
+    pub bpm: f64,
+    pub metronome_on: bool,
+    pub count_in: u32,
+    pub selected_recording: usize,
+    pub cc_bindings: Vec<CcBinding>,
     accompaniments: Vec<Recording>,
     solos: Vec<Recording>,
+    // Index of the accompaniment each solo was recorded over, aligned with
+    // `solos`, so a solo is never graded against the wrong progression.
+    solo_accompaniment: Vec<usize>,
     solo_duration: Option<f64>,
     incoming: Arc<SegQueue<SynthMsg>>,
     outgoing: Arc<SegQueue<SynthMsg>>,
     last_msg: Instant,
     current_start: Instant,
     input_port_name: String,
+    metronome_quit: Arc<AtomicCell<bool>>,
+    metronome_running: bool,
+    metronome_start: Instant,
+    script: Option<ScriptEngine>,
 }
 
 impl Recorder {
@@ -40,6 +104,7 @@ impl Recorder {
             timeout,
             accompaniments: vec![],
             solos: vec![],
+            solo_accompaniment: vec![],
             solo_duration: None,
             incoming,
             outgoing,
@@ -47,9 +112,33 @@ impl Recorder {
             current_start: Instant::now(),
             input_port_name,
             mode: RecordingMode::Playthrough,
+            bpm: 120.0,
+            metronome_on: false,
+            count_in: 4,
+            selected_recording: 0,
+            cc_bindings: vec![
+                CcBinding { controller: 1, target: ControlTarget::Timeout },
+                CcBinding { controller: 64, target: ControlTarget::CycleMode },
+                CcBinding { controller: 2, target: ControlTarget::SelectRecording },
+            ],
+            metronome_quit: Arc::new(AtomicCell::new(true)),
+            metronome_running: false,
+            metronome_start: Instant::now(),
+            script: ScriptEngine::load(Path::new("config.rhai")).ok(),
         }
     }
 
+    // Ask the user's script to score a played note, or `None` when no script
+    // is loaded.
+    pub fn score_note(&mut self, ctx: &NoteContext) -> Option<i64> {
+        self.script.as_mut().and_then(|s| s.score_note(ctx))
+    }
+
+    // Ask the user's script for the next generated pitch.
+    pub fn next_note(&mut self, ctx: &NoteContext) -> Option<i64> {
+        self.script.as_mut().and_then(|s| s.next_note(ctx))
+    }
+
     pub fn len(&self) -> usize {
         self.accompaniments.len()
     }
@@ -62,6 +151,10 @@ impl Recorder {
         self.input_port_name.as_str()
     }
 
+    pub fn set_input_port_name(&mut self, name: &str) {
+        self.input_port_name = name.to_string();
+    }
+
     pub fn actively_recording(&self) -> bool {
         !self.accompaniments.is_empty()
             && Instant::now().duration_since(self.last_msg).as_secs_f64() < self.timeout
@@ -75,6 +168,9 @@ impl Recorder {
         match self.mode {
             RecordingMode::Playthrough => {}
             RecordingMode::Record => {
+                if self.counting_in() {
+                    return;
+                }
                 let now = Instant::now();
                 if !self.actively_recording() {
                     self.accompaniments.push(Recording::default());
@@ -106,7 +202,12 @@ impl Recorder {
         let backing = self.accompaniments[selected].clone();
         self.solo_duration = Some(backing.duration());
         self.solos.push(Recording::default());
+        self.solo_accompaniment.push(selected);
         self.current_start = Instant::now();
+        // Click along with the backing track so the solo lines up with it.
+        if self.metronome_on && !self.metronome_running {
+            self.start_metronome();
+        }
         let incoming = self.incoming.clone();
         let outgoing = self.outgoing.clone();
         std::thread::spawn(move || {
@@ -117,6 +218,289 @@ impl Recorder {
             incoming.push(SynthMsg::all_notes_off(midi_fundsp::io::Speaker::Both));
         });
     }
+
+    // Apply an incoming control-change to every parameter bound to its
+    // controller number. A footswitch (CycleMode) only fires on the press half
+    // of its travel. Returns whether the controller matched a binding, so the
+    // caller can pass unmapped CCs through to the synth as before.
+    pub fn handle_cc(&mut self, controller: u8, value: u8) -> bool {
+        let targets: Vec<ControlTarget> = self
+            .cc_bindings
+            .iter()
+            .filter(|b| b.controller == controller)
+            .map(|b| b.target)
+            .collect();
+        let matched = !targets.is_empty();
+        for target in targets {
+            match target {
+                ControlTarget::Timeout => {
+                    self.timeout =
+                        MIN_TIMEOUT + value as f64 / 127.0 * (MAX_TIMEOUT - MIN_TIMEOUT);
+                }
+                ControlTarget::CycleMode => {
+                    if value >= 64 {
+                        self.mode = next(&self.mode)
+                            .unwrap_or_else(|| first::<RecordingMode>().unwrap());
+                    }
+                }
+                ControlTarget::SelectRecording => {
+                    if !self.is_empty() {
+                        self.selected_recording =
+                            (value as usize * self.len() / 128).min(self.len() - 1);
+                    }
+                }
+            }
+        }
+        matched
+    }
+
+    // Turn the metronome on or off, spawning or signalling its timer thread.
+    pub fn set_metronome(&mut self, on: bool) {
+        self.metronome_on = on;
+        if on {
+            if !self.metronome_running {
+                self.start_metronome();
+            }
+        } else {
+            self.stop_metronome();
+        }
+    }
+
+    pub fn stop_metronome(&mut self) {
+        self.metronome_quit.store(true);
+        self.metronome_running = false;
+    }
+
+    // True while the leading count-in beats are still playing, so `receive`
+    // drops the notes played before the downbeat.
+    pub fn counting_in(&self) -> bool {
+        self.metronome_running
+            && Instant::now().duration_since(self.metronome_start).as_secs_f64()
+                < self.count_in as f64 * 60.0 / self.bpm
+    }
+
+    // Change the tempo, restarting the timer thread so a running metronome
+    // picks up the new beat length.
+    pub fn set_bpm(&mut self, bpm: f64) {
+        self.bpm = bpm;
+        if self.metronome_running {
+            self.stop_metronome();
+            self.start_metronome();
+        }
+    }
+
+    // Spawn a timer thread that pushes a click on the reserved channel every
+    // beat, analogous to how `start_solo_thread` drives playback.
+    fn start_metronome(&mut self) {
+        // A fresh flag per thread: signalling the old thread to quit must not
+        // be undone by the next start, or two click threads can overlap.
+        self.metronome_quit = Arc::new(AtomicCell::new(false));
+        self.metronome_start = Instant::now();
+        self.metronome_running = true;
+        let outgoing = self.outgoing.clone();
+        let quit = self.metronome_quit.clone();
+        let beat = Duration::from_secs_f64(60.0 / self.bpm);
+        std::thread::spawn(move || {
+            while !quit.load() {
+                outgoing.push(click_msg(CLICK_VELOCITY));
+                std::thread::sleep(CLICK_LENGTH.min(beat));
+                outgoing.push(click_msg(0));
+                if beat > CLICK_LENGTH {
+                    std::thread::sleep(beat - CLICK_LENGTH);
+                }
+            }
+        });
+    }
+
+    // Generate a Version 3 target line from an accompaniment and one of the
+    // user's solos over it.
+    pub fn generate_target(&mut self, accompaniment: usize, solo: usize) -> Recording {
+        generator::generate(
+            &self.accompaniments[accompaniment],
+            &self.solos[solo],
+            &mut self.script,
+        )
+    }
+
+    // Grade one of the user's solos over an accompaniment, note by note. Each
+    // note's score comes from the user's `score_note` script when present,
+    // falling back to the built-in rule (in-scale tones and continued figures
+    // earn points; anything else loses one).
+    pub fn score_solo(&mut self, accompaniment: usize, solo: usize) -> i64 {
+        let contexts = generator::note_contexts(&self.accompaniments[accompaniment], &self.solos[solo]);
+        contexts
+            .iter()
+            .map(|ctx| self.score_note(ctx).unwrap_or_else(|| builtin_score(ctx)))
+            .sum()
+    }
+
+    // Play a generated target back like a solo backing track, recording the
+    // user's attempt to match it.
+    pub fn start_target_thread(&mut self, accompaniment: usize, target: Recording) {
+        self.solo_duration = Some(target.duration());
+        self.solos.push(Recording::default());
+        self.solo_accompaniment.push(accompaniment);
+        self.current_start = Instant::now();
+        if self.metronome_on && !self.metronome_running {
+            self.start_metronome();
+        }
+        let incoming = self.incoming.clone();
+        let outgoing = self.outgoing.clone();
+        std::thread::spawn(move || {
+            target.playback_loop(None, outgoing, |msg| SynthMsg {
+                msg,
+                speaker: midi_fundsp::io::Speaker::Both,
+            });
+            incoming.push(SynthMsg::all_notes_off(midi_fundsp::io::Speaker::Both));
+        });
+    }
+
+    // Index of the most recently recorded solo, if any.
+    pub fn last_solo(&self) -> Option<usize> {
+        self.solos.len().checked_sub(1)
+    }
+
+    // Index of the most recent solo recorded over a given accompaniment, so
+    // generation and scoring only ever pair a solo with its own progression.
+    pub fn last_solo_over(&self, accompaniment: usize) -> Option<usize> {
+        (0..self.solos.len())
+            .rev()
+            .find(|&i| self.solo_accompaniment[i] == accompaniment)
+    }
+
+    // Score the most recent attempt against a target by total pitch distance.
+    pub fn score_last_attempt(&self, target: &Recording) -> Option<i64> {
+        self.solos
+            .last()
+            .map(|attempt| generator::score_distance(target, attempt))
+    }
+
+    pub fn save_smf(&self, index: usize, path: &Path) -> io::Result<()> {
+        smf::write_type0(path, &smf_events(&self.accompaniments[index]))
+    }
+
+    // Merge an accompaniment and one of its solos onto separate tracks of a
+    // single type-1 file.
+    pub fn save_smf_with_solo(
+        &self,
+        accompaniment: usize,
+        solo: usize,
+        path: &Path,
+    ) -> io::Result<()> {
+        let backing = smf_events(&self.accompaniments[accompaniment]);
+        let over = smf_events(&self.solos[solo]);
+        smf::write_type1(path, &[&backing, &over])
+    }
+}
+
+// Built-in fallback for `score_solo`: a note scores one point for landing in
+// the chord's scale and another for continuing a melodic figure (the last two
+// intervals matching); a note that does neither loses a point.
+fn builtin_score(ctx: &NoteContext) -> i64 {
+    let mut score = 0;
+    if ctx.in_scale {
+        score += 1;
+    }
+    let len = ctx.figure.len();
+    if len >= 2 && ctx.figure[len - 1] == ctx.figure[len - 2] {
+        score += 1;
+    }
+    if score == 0 {
+        -1
+    } else {
+        score
+    }
+}
+
+// A click on the reserved metronome channel; velocity 0 releases it.
+fn click_msg(velocity: u8) -> SynthMsg {
+    let bytes = [0x90 | METRONOME_CHANNEL, CLICK_NOTE, velocity];
+    SynthMsg {
+        msg: MidiMsg::from_midi(&bytes).unwrap().0,
+        speaker: midi_fundsp::io::Speaker::Both,
+    }
+}
+
+fn smf_events(recording: &Recording) -> Vec<smf::Event> {
+    recording
+        .messages()
+        .iter()
+        .map(|(time, msg)| (*time, msg.to_midi()))
+        .collect()
+}
+
+impl Recorder {
+    // Offline bounce: replay an accompaniment (and optionally a solo over it)
+    // through the synth and write the mixed samples to a WAV file, without
+    // going through the live audio device.
+    pub fn save_wav(&self, accompaniment: usize, solo: Option<usize>, path: &Path) -> io::Result<()> {
+        let mut events = smf_events(&self.accompaniments[accompaniment]);
+        if let Some(solo) = solo {
+            events.extend(smf_events(&self.solos[solo]));
+        }
+        wav::write_i16(path, SAMPLE_RATE, &render(&events))
+    }
+}
+
+// Render timestamped note messages into a mono sample buffer.
+//
+// midi_fundsp's synth path (`start_output_thread`) drives a live real-time
+// output stream and offers no offline render entry point, so the bounce uses a
+// standalone voice here rather than reaching into that path: each note is a
+// sine scaled by velocity, with short linear fades so onsets and releases
+// don't click. This approximates — but does not reproduce — the live timbre.
+fn render(events: &[smf::Event]) -> Vec<f32> {
+    let notes = sounding_notes(events);
+    let end = notes.iter().map(|n| n.off).fold(0.0, f64::max);
+    let mut buffer = vec![0.0; (end * SAMPLE_RATE as f64).ceil() as usize + 1];
+    for note in notes {
+        let freq = 440.0 * 2f32.powf((note.pitch as f32 - 69.0) / 12.0);
+        let amplitude = note.velocity as f32 / 127.0 * 0.2;
+        let start = (note.on * SAMPLE_RATE as f64) as usize;
+        let stop = (note.off * SAMPLE_RATE as f64) as usize;
+        let fade = (SAMPLE_RATE / 200).max(1) as usize;
+        for i in start..stop.min(buffer.len()) {
+            let progress = i - start;
+            let remaining = stop - i;
+            let envelope = (progress.min(fade) as f32 / fade as f32)
+                .min(remaining.min(fade) as f32 / fade as f32);
+            let t = progress as f32 / SAMPLE_RATE as f32;
+            buffer[i] += amplitude * envelope * (TAU * freq * t).sin();
+        }
+    }
+    buffer
+}
+
+struct SoundingNote {
+    pitch: u8,
+    velocity: u8,
+    on: f64,
+    off: f64,
+}
+
+// Pair note-on messages with their matching note-off (or zero-velocity note-on)
+// by pitch, using the raw status nibble so we don't depend on the message enum.
+fn sounding_notes(events: &[smf::Event]) -> Vec<SoundingNote> {
+    let mut result = vec![];
+    let mut active: HashMap<u8, (f64, u8)> = HashMap::new();
+    for (time, message) in events {
+        if message.len() < 3 {
+            continue;
+        }
+        let (status, pitch, velocity) = (message[0] & 0xf0, message[1], message[2]);
+        match status {
+            0x90 if velocity > 0 => {
+                active.insert(pitch, (*time, velocity));
+            }
+            0x80 | 0x90 => {
+                if let Some((on, velocity)) = active.remove(&pitch) {
+                    result.push(SoundingNote { pitch, velocity, on, off: *time });
+                }
+            }
+            _ => {}
+        }
+    }
+    result
 }
 
 impl Index<usize> for Recorder {
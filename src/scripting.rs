@@ -0,0 +1,120 @@
+// Pluggable scoring and melody-generation rules via embedded Rhai.
+//
+// A user drops a `config.rhai` in the working directory with `fn score_note(ctx)`
+// and `fn next_note(ctx)` functions. `ctx` is a map describing the note in
+// context: the active chord, whether the played pitch is in that chord's
+// scale, the recent melodic intervals, and the elapsed time. Each callback
+// keeps its own persistent `this` map so scripts can accumulate state (running
+// streaks, the last interval) across notes.
+//
+// `ScriptEngine` is held inside `Recorder`, which the monitor thread shares as
+// `Arc<Mutex<Recorder>>`, so `Recorder` must be `Send`. rhai's `Dynamic` (and
+// therefore the stored `this` values) is only `Send` with the crate's `sync`
+// feature, so the dependency must be declared `rhai = { features = ["sync"] }`.
+
+use rhai::{CallFnOptions, Dynamic, Engine, Map, Scope, AST};
+use std::path::Path;
+
+// The active chord is exposed to scripts by its display name (the string form
+// of the `ChordName`), so callers format it at the boundary.
+pub struct NoteContext {
+    pub chord: String,
+    pub in_scale: bool,
+    pub figure: Vec<i64>,
+    pub elapsed: f64,
+}
+
+impl NoteContext {
+    fn to_map(&self) -> Map {
+        let figure: rhai::Array = self.figure.iter().map(|i| Dynamic::from_int(*i)).collect();
+        let mut map = Map::new();
+        map.insert("chord".into(), self.chord.clone().into());
+        map.insert("in_scale".into(), self.in_scale.into());
+        map.insert("figure".into(), figure.into());
+        map.insert("elapsed".into(), self.elapsed.into());
+        map
+    }
+}
+
+pub struct ScriptEngine {
+    engine: Engine,
+    ast: AST,
+    score_this: Dynamic,
+    next_this: Dynamic,
+}
+
+impl ScriptEngine {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let engine = Engine::new();
+        let source = std::fs::read_to_string(path)?;
+        let ast = engine
+            .compile(&source)
+            .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+        Ok(Self {
+            engine,
+            ast,
+            score_this: Dynamic::from(Map::new()),
+            next_this: Dynamic::from(Map::new()),
+        })
+    }
+
+    pub fn score_note(&mut self, ctx: &NoteContext) -> Option<i64> {
+        call_int(&self.engine, &self.ast, &mut self.score_this, "score_note", ctx)
+    }
+
+    pub fn next_note(&mut self, ctx: &NoteContext) -> Option<i64> {
+        call_int(&self.engine, &self.ast, &mut self.next_this, "next_note", ctx)
+    }
+}
+
+// Invoke a user function with its persistent `this` bound, returning the
+// integer result (a score or a pitch) or `None` if the script is missing the
+// function or returns the wrong type.
+fn call_int(
+    engine: &Engine,
+    ast: &AST,
+    this: &mut Dynamic,
+    name: &str,
+    ctx: &NoteContext,
+) -> Option<i64> {
+    let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(this);
+    let mut scope = Scope::new();
+    let result: Result<Dynamic, _> =
+        engine.call_fn_with_options(options, &mut scope, ast, name, (ctx.to_map(),));
+    result.ok().and_then(|value| value.as_int().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(in_scale: bool) -> NoteContext {
+        NoteContext { chord: "C".to_string(), in_scale, figure: vec![], elapsed: 0.0 }
+    }
+
+    #[test]
+    fn script_drives_scoring_and_generation() {
+        let path = std::env::temp_dir().join("improv_hero_config_test.rhai");
+        std::fs::write(
+            &path,
+            r#"
+                fn score_note(ctx) { if ctx.in_scale { 1 } else { -1 } }
+                fn next_note(ctx) {
+                    this.n = if "n" in this { this.n + 1 } else { 60 };
+                    this.n
+                }
+            "#,
+        )
+        .unwrap();
+
+        let mut engine = ScriptEngine::load(&path).unwrap();
+        assert_eq!(engine.score_note(&context(true)), Some(1));
+        assert_eq!(engine.score_note(&context(false)), Some(-1));
+
+        // `this` persists across calls, so the pitch advances each time.
+        assert_eq!(engine.next_note(&context(true)), Some(60));
+        assert_eq!(engine.next_note(&context(true)), Some(61));
+
+        std::fs::remove_file(&path).ok();
+    }
+}
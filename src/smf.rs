@@ -0,0 +1,149 @@
+// Minimal Standard MIDI File writer.
+//
+// Serializes sequences of timestamped (seconds) raw MIDI messages into
+// type-0 (single track) or type-1 (one track per part) `.mid` files that any
+// DAW can load. We only need the pieces the recorder produces: an `MThd`
+// header, one `MTrk` per track with variable-length delta times, an optional
+// leading tempo meta event, and a closing end-of-track.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+pub const DEFAULT_DIVISION: u16 = 480;
+pub const DEFAULT_TEMPO_BPM: f64 = 120.0;
+
+// Absolute time in seconds plus the raw status/data bytes of a MIDI message,
+// matching how a `Recording` stores its events.
+pub type Event = (f64, Vec<u8>);
+
+// Variable-length quantity: 7 bits per byte, most-significant group first,
+// bit 7 set on every byte except the last (0 -> 00, 128 -> 81 00).
+fn push_vlq(value: u32, out: &mut Vec<u8>) {
+    let mut buffer = value & 0x7f;
+    let mut value = value >> 7;
+    while value > 0 {
+        buffer <<= 8;
+        buffer |= (value & 0x7f) | 0x80;
+        value >>= 7;
+    }
+    loop {
+        out.push((buffer & 0xff) as u8);
+        if buffer & 0x80 == 0 {
+            break;
+        }
+        buffer >>= 8;
+    }
+}
+
+fn seconds_to_ticks(seconds: f64, bpm: f64, division: u16) -> u64 {
+    (seconds * bpm / 60.0 * division as f64).round() as u64
+}
+
+// A named chunk: four ASCII id bytes, a big-endian length, then the body.
+fn chunk(id: &[u8; 4], body: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    out.extend_from_slice(body);
+}
+
+// One `MTrk` body: each event prefixed by its delta from the previous event,
+// optionally led by a tempo meta event, closed by end-of-track (FF 2F 00).
+fn track_body(events: &[Event], bpm: f64, division: u16, tempo: bool) -> Vec<u8> {
+    let mut body = vec![];
+    if tempo {
+        let micros_per_quarter = (60_000_000.0 / bpm).round() as u32;
+        push_vlq(0, &mut body);
+        body.extend_from_slice(&[0xff, 0x51, 0x03]);
+        body.extend_from_slice(&micros_per_quarter.to_be_bytes()[1..]);
+    }
+    let mut last_tick = 0;
+    for (seconds, message) in events {
+        let tick = seconds_to_ticks(*seconds, bpm, division);
+        push_vlq((tick - last_tick) as u32, &mut body);
+        body.extend_from_slice(message);
+        last_tick = tick;
+    }
+    push_vlq(0, &mut body);
+    body.extend_from_slice(&[0xff, 0x2f, 0x00]);
+    body
+}
+
+fn encode(division: u16, bpm: f64, tracks: &[&[Event]]) -> Vec<u8> {
+    let format: u16 = if tracks.len() > 1 { 1 } else { 0 };
+    let mut bytes = vec![];
+    let mut header = vec![];
+    header.extend_from_slice(&format.to_be_bytes());
+    header.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+    header.extend_from_slice(&division.to_be_bytes());
+    chunk(b"MThd", &header, &mut bytes);
+    for (i, events) in tracks.iter().enumerate() {
+        let body = track_body(events, bpm, division, i == 0);
+        chunk(b"MTrk", &body, &mut bytes);
+    }
+    bytes
+}
+
+fn write(path: &Path, division: u16, bpm: f64, tracks: &[&[Event]]) -> io::Result<()> {
+    File::create(path)?.write_all(&encode(division, bpm, tracks))
+}
+
+pub fn write_type0(path: &Path, events: &[Event]) -> io::Result<()> {
+    write(path, DEFAULT_DIVISION, DEFAULT_TEMPO_BPM, &[events])
+}
+
+pub fn write_type1(path: &Path, tracks: &[&[Event]]) -> io::Result<()> {
+    write(path, DEFAULT_DIVISION, DEFAULT_TEMPO_BPM, tracks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vlq(value: u32) -> Vec<u8> {
+        let mut out = vec![];
+        push_vlq(value, &mut out);
+        out
+    }
+
+    #[test]
+    fn vlq_matches_spec() {
+        assert_eq!(vlq(0), vec![0x00]);
+        assert_eq!(vlq(127), vec![0x7f]);
+        assert_eq!(vlq(128), vec![0x81, 0x00]);
+        assert_eq!(vlq(0x4000), vec![0x81, 0x80, 0x00]);
+        assert_eq!(vlq(0x0fffffff), vec![0xff, 0xff, 0xff, 0x7f]);
+    }
+
+    #[test]
+    fn type0_header_and_framing() {
+        let note_on = vec![0x90, 60, 100];
+        let note_off = vec![0x80, 60, 0];
+        let events = vec![(0.0, note_on), (0.5, note_off)];
+        let bytes = encode(DEFAULT_DIVISION, DEFAULT_TEMPO_BPM, &[&events]);
+
+        // MThd: "MThd", length 6, format 0, one track, the requested division.
+        assert_eq!(&bytes[0..4], b"MThd");
+        assert_eq!(&bytes[4..8], &6u32.to_be_bytes());
+        assert_eq!(&bytes[8..10], &0u16.to_be_bytes());
+        assert_eq!(&bytes[10..12], &1u16.to_be_bytes());
+        assert_eq!(&bytes[12..14], &DEFAULT_DIVISION.to_be_bytes());
+
+        // One MTrk chunk whose declared length matches its body.
+        assert_eq!(&bytes[14..18], b"MTrk");
+        let len = u32::from_be_bytes(bytes[18..22].try_into().unwrap()) as usize;
+        assert_eq!(22 + len, bytes.len());
+
+        // The track closes with the end-of-track meta event.
+        assert_eq!(&bytes[bytes.len() - 3..], &[0xff, 0x2f, 0x00]);
+    }
+
+    #[test]
+    fn type1_writes_one_track_per_part() {
+        let part = vec![(0.0, vec![0x90, 64, 80]), (0.25, vec![0x80, 64, 0])];
+        let bytes = encode(DEFAULT_DIVISION, DEFAULT_TEMPO_BPM, &[&part, &part]);
+        assert_eq!(&bytes[8..10], &1u16.to_be_bytes());
+        assert_eq!(&bytes[10..12], &2u16.to_be_bytes());
+        assert_eq!(bytes.windows(4).filter(|w| *w == b"MTrk").count(), 2);
+    }
+}
@@ -0,0 +1,44 @@
+// Minimal RIFF/WAVE writer for 16-bit PCM.
+//
+// Enough to bounce an improv session to a shareable clip: a 44-byte header
+// (the "RIFF"/"WAVE" container, a "fmt " subchunk describing the PCM stream,
+// and a "data" subchunk) followed by the interleaved samples as little-endian
+// i16. Input is a mono f32 buffer in -1.0..=1.0, as produced by the offline
+// bounce.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+const BITS_PER_SAMPLE: u16 = 16;
+const CHANNELS: u16 = 1;
+const PCM_FORMAT: u16 = 1;
+
+pub fn write_i16(path: &Path, sample_rate: u32, samples: &[f32]) -> io::Result<()> {
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    let byte_rate = sample_rate * block_align as u32;
+    let data_len = (samples.len() * 2) as u32;
+
+    let mut bytes = vec![];
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(36 + data_len).to_le_bytes());
+    bytes.extend_from_slice(b"WAVE");
+
+    bytes.extend_from_slice(b"fmt ");
+    bytes.extend_from_slice(&16u32.to_le_bytes());
+    bytes.extend_from_slice(&PCM_FORMAT.to_le_bytes());
+    bytes.extend_from_slice(&CHANNELS.to_le_bytes());
+    bytes.extend_from_slice(&sample_rate.to_le_bytes());
+    bytes.extend_from_slice(&byte_rate.to_le_bytes());
+    bytes.extend_from_slice(&block_align.to_le_bytes());
+    bytes.extend_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        let clamped = sample.clamp(-1.0, 1.0);
+        bytes.extend_from_slice(&((clamped * i16::MAX as f32) as i16).to_le_bytes());
+    }
+
+    File::create(path)?.write_all(&bytes)
+}